@@ -7,12 +7,18 @@ use crate::transport::{
 };
 
 use futures::{future::RemoteHandle, FutureExt};
+use rand::Rng;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
 
 /// ConnectionKeeper keeps a Connection to some address and works to keep it open
 pub struct ConnectionKeeper {
     conn_state_receiver: tokio::sync::watch::Receiver<ConnectionState>,
+    shutdown: Arc<Shutdown>,
+    used_keyspace: SharedKeyspace,
     _worker_handle: RemoteHandle<()>,
 }
 
@@ -21,6 +27,14 @@ pub enum ConnectionState {
     Initializing, // First connect attempt ongoing
     Connected(Arc<Connection>),
     Broken(QueryError),
+    Closed, // Shut down on purpose, will not reconnect
+}
+
+/// Shared shutdown signal: `notify` wakes the worker up wherever it's currently
+/// waiting, `drain` tells it whether to wait for in-flight requests to finish first.
+struct Shutdown {
+    notify: tokio::sync::Notify,
+    drain: AtomicBool,
 }
 
 /// Works in the background to keep the connection open
@@ -31,13 +45,24 @@ struct ConnectionKeeperWorker {
 
     shard_info_sender: Option<ShardInfoSender>,
     conn_state_sender: tokio::sync::watch::Sender<ConnectionState>,
+    shutdown: Arc<Shutdown>,
 
-    // Keyspace send in "USE <keyspace name>" when opening each connection
-    used_keyspace: Option<VerifiedKeyspaceName>,
+    // Keyspace sent in "USE <keyspace name>" when opening each connection. Shared
+    // (rather than captured by value) so `ConnectionKeeper::use_keyspace` can update
+    // it and have it survive reconnects, instead of only the connection alive at
+    // the time of the call.
+    used_keyspace: SharedKeyspace,
 }
 
 pub type ShardInfoSender = Arc<std::sync::Mutex<tokio::sync::watch::Sender<Option<ShardInfo>>>>;
 
+type SharedKeyspace = Arc<std::sync::Mutex<Option<VerifiedKeyspaceName>>>;
+
+/// Receives an error as soon as the `Connection` it was handed out alongside
+/// detects that its socket/protocol layer has failed (broken pipe, server-initiated
+/// close, protocol error, ...). The underlying connection's reader task is the sender.
+pub type ErrorReceiver = tokio::sync::mpsc::Receiver<QueryError>;
+
 impl ConnectionKeeper {
     /// Creates new ConnectionKeeper that starts a connection in the background
     /// # Arguments
@@ -56,13 +81,21 @@ impl ConnectionKeeper {
         let (conn_state_sender, conn_state_receiver) =
             tokio::sync::watch::channel(ConnectionState::Initializing);
 
+        let shutdown = Arc::new(Shutdown {
+            notify: tokio::sync::Notify::new(),
+            drain: AtomicBool::new(false),
+        });
+
+        let used_keyspace: SharedKeyspace = Arc::new(std::sync::Mutex::new(keyspace_name));
+
         let worker = ConnectionKeeperWorker {
             address,
             config,
             shard_info,
             shard_info_sender,
             conn_state_sender,
-            used_keyspace: keyspace_name,
+            shutdown: shutdown.clone(),
+            used_keyspace: used_keyspace.clone(),
         };
 
         let (fut, worker_handle) = worker.work().remote_handle();
@@ -70,10 +103,24 @@ impl ConnectionKeeper {
 
         ConnectionKeeper {
             conn_state_receiver,
+            shutdown,
+            used_keyspace,
             _worker_handle: worker_handle,
         }
     }
 
+    /// Requests the worker to stop keeping this connection open. The worker reacts
+    /// wherever it currently is (connecting, connected, or backing off) and publishes
+    /// `ConnectionState::Closed` once done; it will not reconnect afterwards.
+    ///
+    /// If `drain` is true, and a connection is currently held, the worker waits (up
+    /// to `ConnectionConfig::shutdown_drain_timeout`) for its in-flight requests to
+    /// complete before closing it. If `drain` is false it closes immediately.
+    pub fn shutdown(&self, drain: bool) {
+        self.shutdown.drain.store(drain, Ordering::SeqCst);
+        self.shutdown.notify.notify_one();
+    }
+
     /// Get current connection state, returns immediately
     pub fn connection_state(&self) -> ConnectionState {
         self.conn_state_receiver.borrow().clone()
@@ -107,7 +154,8 @@ impl ConnectionKeeper {
         match self.connection_state() {
             ConnectionState::Connected(conn) => Ok(conn),
             ConnectionState::Broken(e) => Err(e),
-            _ => unreachable!(),
+            ConnectionState::Closed => Err(connection_closed_error()),
+            ConnectionState::Initializing => unreachable!(),
         }
     }
 
@@ -115,9 +163,12 @@ impl ConnectionKeeper {
         &self,
         keyspace_name: &VerifiedKeyspaceName,
     ) -> Result<(), QueryError> {
-        // ConnectionKeeper doesn't have reconnecting yet so this will be ok for now
-        // TODO: Modify once ConnectionKeeper gets reconnecting
+        // Stored so that reconnects (handled by the worker) re-apply it to every
+        // connection opened from now on, not just the one we're about to update below.
+        *self.used_keyspace.lock().unwrap() = Some(keyspace_name.clone());
 
+        // The connection we're currently holding predates this call and won't pick
+        // the keyspace up on its own, so it still needs it applied directly here.
         self.get_connection()
             .await?
             .use_keyspace(keyspace_name)
@@ -126,49 +177,266 @@ impl ConnectionKeeper {
 }
 
 impl ConnectionKeeperWorker {
+    /// Runs until shut down, keeping a Connection open.
+    ///
+    /// Whenever the held connection dies, or a connection attempt fails,
+    /// a new one is opened after a truncated exponential backoff with full
+    /// jitter: `delay = min(max_backoff, base * 2^attempt)`, then a uniformly
+    /// random sleep in `[0, delay]`. `attempt` resets to 0 once a connection
+    /// has stayed healthy past `reconnection_reset_threshold`, so a flaky
+    /// network doesn't ratchet the backoff up forever. Cycles
+    /// `ConnectionState` through Initializing -> Connected -> Broken -> Connected
+    /// until `ConnectionKeeper::shutdown` is called, at which point it settles on
+    /// `Closed` instead of reconnecting.
     pub async fn work(self) {
-        let cur_connection = self.open_new_connection().await;
-
-        match &cur_connection {
-            Ok(conn) => {
-                let _ = self
-                    .conn_state_sender
-                    .send(ConnectionState::Connected(conn.clone()));
-
-                let new_shard_info: Option<ShardInfo> = conn.get_shard_info().clone();
-
-                if let Some(sender) = &self.shard_info_sender {
-                    // Ignore sending error
-                    // If no one wants to get shard_info that's OK
-                    // If lock is poisoned do nothing
-                    if let Ok(sender_locked) = sender.lock() {
-                        let _ = sender_locked.send(new_shard_info);
+        let mut attempt: u32 = 0;
+
+        loop {
+            let connect_outcome = tokio::select! {
+                biased;
+                _ = self.shutdown.notify.notified() => None,
+                result = self.open_new_connection() => Some(result),
+            };
+
+            let (conn, error_receiver) = match connect_outcome {
+                None => break,
+                Some(Ok(pair)) => pair,
+                Some(Err(e)) => {
+                    let _ = self.conn_state_sender.send(ConnectionState::Broken(e));
+                    attempt = attempt.saturating_add(1);
+
+                    if !self.sleep_or_shutdown(attempt).await {
+                        break;
+                    }
+                    continue;
+                }
+            };
+
+            let _ = self
+                .conn_state_sender
+                .send(ConnectionState::Connected(conn.clone()));
+
+            self.publish_shard_info(&conn);
+
+            let connected_at = Instant::now();
+
+            let error_outcome = tokio::select! {
+                biased;
+                _ = self.shutdown.notify.notified() => None,
+                error = self.wait_for_connection_error(&conn, error_receiver) => Some(error),
+            };
+
+            let error = match error_outcome {
+                Some(error) => error,
+                None => {
+                    self.close_connection(&conn).await;
+                    break;
+                }
+            };
+
+            let _ = self.conn_state_sender.send(ConnectionState::Broken(error));
+
+            attempt = next_attempt(
+                attempt,
+                connected_at.elapsed(),
+                self.config.reconnection_reset_threshold,
+            );
+
+            if !self.sleep_or_shutdown(attempt).await {
+                break;
+            }
+        }
+
+        let _ = self.conn_state_sender.send(ConnectionState::Closed);
+    }
+
+    /// Closes `conn`, optionally draining it first (waiting, up to a timeout, for
+    /// its in-flight requests to complete) depending on how `shutdown` was called.
+    async fn close_connection(&self, conn: &Arc<Connection>) {
+        if self.shutdown.drain.load(Ordering::SeqCst) {
+            // Ignore the timeout: whether or not in-flight requests finished in
+            // time, we close the connection below either way.
+            let _ =
+                tokio::time::timeout(self.config.shutdown_drain_timeout, conn.wait_until_idle())
+                    .await;
+        }
+
+        conn.close().await;
+    }
+
+    /// Sleeps out the reconnection backoff, or returns early if shut down while
+    /// waiting. Returns `false` if shutdown was requested, `true` otherwise.
+    async fn sleep_or_shutdown(&self, attempt: u32) -> bool {
+        tokio::select! {
+            biased;
+            _ = self.shutdown.notify.notified() => false,
+            _ = self.sleep_before_reconnecting(attempt) => true,
+        }
+    }
+
+    /// Waits until the held connection reports that it has failed, reacting
+    /// immediately instead of polling. While waiting, also drives the optional
+    /// keepalive heartbeat: this only runs while we hold a `Connected` connection,
+    /// so it naturally can't race with the reconnect loop (there's nothing to race,
+    /// a new heartbeat only starts once this call returns and a fresh connection
+    /// is published).
+    async fn wait_for_connection_error(
+        &self,
+        conn: &Arc<Connection>,
+        mut error_receiver: ErrorReceiver,
+    ) -> QueryError {
+        let mut keepalive_ticker = self.config.keepalive_interval.map(tokio::time::interval);
+
+        loop {
+            tokio::select! {
+                maybe_error = error_receiver.recv() => return maybe_error.unwrap_or_else(connection_closed_error),
+                _ = Self::tick(&mut keepalive_ticker) => {
+                    if let Err(e) = self.send_heartbeat(conn).await {
+                        return e;
                     }
                 }
             }
-            Err(e) => {
-                let _ = self
-                    .conn_state_sender
-                    .send(ConnectionState::Broken(e.clone()));
-            } // TODO: Wait for connection to fail, then create new, loop it
-        };
+        }
     }
 
-    async fn open_new_connection(&self) -> Result<Arc<Connection>, QueryError> {
+    /// Awaits the next keepalive tick, or never resolves if no keepalive is configured.
+    async fn tick(ticker: &mut Option<tokio::time::Interval>) {
+        match ticker {
+            Some(ticker) => {
+                ticker.tick().await;
+            }
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Sends a cheap no-op request to validate an otherwise-idle connection.
+    /// Skipped if real traffic already flowed through the connection recently,
+    /// so the heartbeat only fires on genuine idleness.
+    async fn send_heartbeat(&self, conn: &Arc<Connection>) -> Result<(), QueryError> {
+        if conn.time_since_last_activity() < self.config.keepalive_interval.unwrap_or_default() {
+            return Ok(());
+        }
+
+        conn.query_single_page("SELECT key FROM system.local WHERE key='local'")
+            .await
+            .map(|_| ())
+    }
+
+    fn publish_shard_info(&self, conn: &Arc<Connection>) {
+        let new_shard_info: Option<ShardInfo> = conn.get_shard_info().clone();
+
+        if let Some(sender) = &self.shard_info_sender {
+            // Ignore sending error
+            // If no one wants to get shard_info that's OK
+            // If lock is poisoned do nothing
+            if let Ok(sender_locked) = sender.lock() {
+                let _ = sender_locked.send(new_shard_info);
+            }
+        }
+    }
+
+    /// Sleeps for a truncated exponential backoff with full jitter, using
+    /// the base delay and cap configured on `ConnectionConfig`.
+    async fn sleep_before_reconnecting(&self, attempt: u32) {
+        let delay = backoff_delay(
+            attempt,
+            self.config.reconnection_base_delay,
+            self.config.reconnection_max_delay,
+        );
+        let jittered_millis = rand::thread_rng().gen_range(0..=delay.as_millis() as u64);
+
+        tokio::time::sleep(Duration::from_millis(jittered_millis)).await;
+    }
+
+    async fn open_new_connection(&self) -> Result<(Arc<Connection>, ErrorReceiver), QueryError> {
         let mut source_port: Option<u16> = None;
         if let Some(info) = &self.shard_info {
             source_port = Some(info.draw_source_port_for_shard(info.shard.into()));
         }
 
-        let new_conn =
+        let (new_conn, error_receiver) =
             connection::open_connection(self.address, source_port, self.config.clone()).await?;
 
-        if let Some(keyspace_name) = &self.used_keyspace {
-            let _ = new_conn.use_keyspace(&keyspace_name).await;
+        // Read fresh on every reconnect, so a keyspace set via
+        // `ConnectionKeeper::use_keyspace` after this worker started still gets
+        // applied to the connections opened afterwards.
+        let keyspace_name = self.used_keyspace.lock().unwrap().clone();
+
+        if let Some(keyspace_name) = &keyspace_name {
+            let _ = new_conn.use_keyspace(keyspace_name).await;
             // Ignore the error, used_keyspace could be set a long time ago and then deleted
             // user gets all errors from session.use_keyspace()
         }
 
-        Ok(Arc::new(new_conn))
+        Ok((Arc::new(new_conn), error_receiver))
+    }
+}
+
+/// Used when the error channel closes without ever sending an error (e.g. the
+/// `Connection` was dropped), so `get_connection()` still has something to report.
+fn connection_closed_error() -> QueryError {
+    QueryError::IoError(Arc::new(std::io::Error::new(
+        std::io::ErrorKind::BrokenPipe,
+        "Connection closed",
+    )))
+}
+
+/// Truncated exponential backoff: `min(cap, base * 2^attempt)`. Jitter is applied
+/// by the caller by sampling uniformly in `[0, backoff_delay(...)]`.
+fn backoff_delay(attempt: u32, base: Duration, cap: Duration) -> Duration {
+    let factor = 2u32.checked_pow(attempt).unwrap_or(u32::MAX);
+    base.saturating_mul(factor).min(cap)
+}
+
+/// Decides the next backoff `attempt` counter: reset to 0 if the previous connection
+/// stayed healthy for at least `reset_threshold`, otherwise keep incrementing.
+fn next_attempt(previous_attempt: u32, connected_for: Duration, reset_threshold: Duration) -> u32 {
+    if connected_for >= reset_threshold {
+        0
+    } else {
+        previous_attempt.saturating_add(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{backoff_delay, next_attempt};
+    use std::time::Duration;
+
+    #[test]
+    fn backoff_delay_grows_exponentially_from_base() {
+        let base = Duration::from_millis(100);
+        let cap = Duration::from_secs(60);
+
+        assert_eq!(backoff_delay(0, base, cap), Duration::from_millis(100));
+        assert_eq!(backoff_delay(1, base, cap), Duration::from_millis(200));
+        assert_eq!(backoff_delay(2, base, cap), Duration::from_millis(400));
+        assert_eq!(backoff_delay(3, base, cap), Duration::from_millis(800));
+    }
+
+    #[test]
+    fn backoff_delay_is_truncated_at_cap() {
+        let base = Duration::from_millis(100);
+        let cap = Duration::from_secs(1);
+
+        assert_eq!(backoff_delay(10, base, cap), cap);
+        // Also shouldn't overflow/panic for very large attempt counts.
+        assert_eq!(backoff_delay(u32::MAX, base, cap), cap);
+    }
+
+    #[test]
+    fn next_attempt_increments_while_unhealthy() {
+        let reset_threshold = Duration::from_secs(30);
+
+        assert_eq!(next_attempt(0, Duration::from_secs(1), reset_threshold), 1);
+        assert_eq!(next_attempt(4, Duration::from_secs(5), reset_threshold), 5);
+    }
+
+    #[test]
+    fn next_attempt_resets_after_staying_healthy_past_threshold() {
+        let reset_threshold = Duration::from_secs(30);
+
+        assert_eq!(next_attempt(7, Duration::from_secs(30), reset_threshold), 0);
+        assert_eq!(next_attempt(7, Duration::from_secs(60), reset_threshold), 0);
     }
 }
@@ -0,0 +1,318 @@
+/// NodeConnectionPool keeps a number of connections open to a single node,
+/// built on top of ConnectionKeeper
+use crate::routing::{ShardInfo, Token};
+use crate::transport::connection::{Connection, ConnectionConfig, VerifiedKeyspaceName};
+use crate::transport::connection_keeper::{ConnectionKeeper, ConnectionState, ShardInfoSender};
+use crate::transport::errors::QueryError;
+
+use rand::Rng;
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+
+/// Decides how many connections a `NodeConnectionPool` maintains to a single node.
+#[derive(Clone, Copy, Debug)]
+pub enum PoolSize {
+    /// Maintain `n` connections to the node, spread evenly without regard to
+    /// shards. Suitable for plain, non-shard-aware Cassandra nodes.
+    PerHost(NonZeroUsize),
+    /// Maintain `n` connections to *each* of the node's shards, using the
+    /// source-port trick (`ShardInfo::draw_source_port_for_shard`) to land
+    /// each connection on the shard it targets.
+    PerShard(NonZeroUsize),
+}
+
+impl Default for PoolSize {
+    fn default() -> Self {
+        PoolSize::PerShard(NonZeroUsize::new(1).unwrap())
+    }
+}
+
+/// Where the pool's keepers actually live. Kept distinct from `PoolSize` because
+/// a `PerShard` pool silently degrades to this flat layout when the node turns
+/// out not to be shard-aware (no `ShardInfo` was learned from the bootstrap connection).
+enum PoolLayout {
+    /// Not shard-aware: connections are spread evenly, with no notion of ownership.
+    Flat(Vec<ConnectionKeeper>),
+    /// Shard-aware: `keepers_by_shard[shard]` holds every keeper pinned to that shard.
+    PerShard {
+        shard_info: ShardInfo,
+        keepers_by_shard: Vec<Vec<ConnectionKeeper>>,
+    },
+}
+
+impl PoolLayout {
+    fn all_keepers(&self) -> Box<dyn Iterator<Item = &ConnectionKeeper> + '_> {
+        match self {
+            PoolLayout::Flat(keepers) => Box::new(keepers.iter()),
+            PoolLayout::PerShard {
+                keepers_by_shard, ..
+            } => Box::new(keepers_by_shard.iter().flatten()),
+        }
+    }
+}
+
+/// Maintains a pool of connections to a single node, distributed across the
+/// node's shards (or evenly, for non-shard-aware nodes) according to `PoolSize`.
+/// Built as a thin layer on top of `ConnectionKeeper`: one keeper per pool slot,
+/// each keeping itself open and reconnecting on its own.
+pub struct NodeConnectionPool {
+    address: SocketAddr,
+    layout: PoolLayout,
+    shard_info_sender: ShardInfoSender,
+    // Shared (rather than a plain field) so that `use_keyspace` updates are picked
+    // up by keepers spawned later too (e.g. if the pool grows after a topology
+    // change), not just the ones alive when `use_keyspace` was called.
+    used_keyspace: SharedKeyspace,
+}
+
+type SharedKeyspace = Arc<std::sync::Mutex<Option<VerifiedKeyspaceName>>>;
+
+impl NodeConnectionPool {
+    /// Creates a new pool and starts opening connections to `address` in the background.
+    /// # Arguments
+    ///
+    /// * `address` - IP address to connect to
+    /// * `pool_size` - how many connections to maintain, and how to spread them over shards
+    /// * `config` - configuration used for every connection opened by the pool
+    /// * `keyspace_name` - keyspace to `USE` on every connection opened by the pool
+    pub async fn new(
+        address: SocketAddr,
+        pool_size: PoolSize,
+        config: ConnectionConfig,
+        keyspace_name: Option<VerifiedKeyspaceName>,
+    ) -> Self {
+        let (shard_info_sender, shard_info_receiver) = tokio::sync::watch::channel(None);
+        let shard_info_sender: ShardInfoSender = Arc::new(std::sync::Mutex::new(shard_info_sender));
+        let used_keyspace: SharedKeyspace = Arc::new(std::sync::Mutex::new(keyspace_name));
+
+        // Bootstrap with a single keeper so we learn the node's ShardInfo (or
+        // confirm it isn't shard-aware) before deciding how many more connections
+        // to open, and where to land them.
+        let first_keeper = ConnectionKeeper::new(
+            address,
+            config.clone(),
+            None,
+            Some(shard_info_sender.clone()),
+            used_keyspace.lock().unwrap().clone(),
+        );
+
+        first_keeper.wait_until_initialized().await;
+
+        let shard_info: Option<ShardInfo> = shard_info_receiver.borrow().clone();
+
+        let layout = match (pool_size, shard_info) {
+            (PoolSize::PerHost(n), _) => {
+                let mut keepers = vec![first_keeper];
+
+                for _ in 1..n.get() {
+                    keepers.push(Self::spawn_keeper(
+                        address,
+                        &config,
+                        None,
+                        &shard_info_sender,
+                        &used_keyspace,
+                    ));
+                }
+
+                PoolLayout::Flat(keepers)
+            }
+            (PoolSize::PerShard(n), Some(info)) => {
+                let mut keepers_by_shard: Vec<Vec<ConnectionKeeper>> =
+                    (0..info.nr_shards.get()).map(|_| Vec::new()).collect();
+
+                // The bootstrap connection already landed on some shard; keep it
+                // there instead of opening a redundant extra one.
+                keepers_by_shard[info.shard as usize].push(first_keeper);
+
+                for shard in 0..info.nr_shards.get() {
+                    let already_have = keepers_by_shard[shard].len();
+
+                    for _ in already_have..n.get() {
+                        let mut shard_info_for_conn = info.clone();
+                        shard_info_for_conn.shard = shard as u16;
+
+                        keepers_by_shard[shard].push(Self::spawn_keeper(
+                            address,
+                            &config,
+                            Some(shard_info_for_conn),
+                            &shard_info_sender,
+                            &used_keyspace,
+                        ));
+                    }
+                }
+
+                PoolLayout::PerShard {
+                    shard_info: info,
+                    keepers_by_shard,
+                }
+            }
+            (PoolSize::PerShard(n), None) => {
+                // Node isn't shard-aware: fall back to spreading connections evenly.
+                let mut keepers = vec![first_keeper];
+
+                for _ in 1..n.get() {
+                    keepers.push(Self::spawn_keeper(
+                        address,
+                        &config,
+                        None,
+                        &shard_info_sender,
+                        &used_keyspace,
+                    ));
+                }
+
+                PoolLayout::Flat(keepers)
+            }
+        };
+
+        NodeConnectionPool {
+            address,
+            layout,
+            shard_info_sender,
+            used_keyspace,
+        }
+    }
+
+    fn spawn_keeper(
+        address: SocketAddr,
+        config: &ConnectionConfig,
+        shard_info: Option<ShardInfo>,
+        shard_info_sender: &ShardInfoSender,
+        used_keyspace: &SharedKeyspace,
+    ) -> ConnectionKeeper {
+        ConnectionKeeper::new(
+            address,
+            config.clone(),
+            shard_info,
+            Some(shard_info_sender.clone()),
+            used_keyspace.lock().unwrap().clone(),
+        )
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Current state of every connection slot in the pool.
+    pub fn connection_states(&self) -> Vec<ConnectionState> {
+        self.layout
+            .all_keepers()
+            .map(ConnectionKeeper::connection_state)
+            .collect()
+    }
+
+    /// Picks a random connection slot from the pool and returns its connection.
+    /// Broken slots are not skipped: if the picked slot is broken, its error is returned.
+    pub async fn random_connection(&self) -> Result<Arc<Connection>, QueryError> {
+        match &self.layout {
+            PoolLayout::Flat(keepers) => {
+                let index = rand::thread_rng().gen_range(0..keepers.len());
+                keepers[index].get_connection().await
+            }
+            PoolLayout::PerShard {
+                shard_info,
+                keepers_by_shard,
+            } => {
+                let shard = rand::thread_rng().gen_range(0..shard_info.nr_shards.get());
+                let keepers = &keepers_by_shard[shard];
+                let index = rand::thread_rng().gen_range(0..keepers.len());
+                keepers[index].get_connection().await
+            }
+        }
+    }
+
+    /// Picks a connection pinned to the shard that owns `token`, using the same
+    /// token-to-shard assignment ScyllaDB itself uses. Falls back to
+    /// `random_connection` for non-shard-aware pools, where there's no such thing
+    /// as shard ownership to route by.
+    pub async fn connection_for_token(&self, token: Token) -> Result<Arc<Connection>, QueryError> {
+        let (shard_info, keepers_by_shard) = match &self.layout {
+            PoolLayout::Flat(_) => return self.random_connection().await,
+            PoolLayout::PerShard {
+                shard_info,
+                keepers_by_shard,
+            } => (shard_info, keepers_by_shard),
+        };
+
+        let shard = shard_info.shard_for_token(token);
+        let keepers = &keepers_by_shard[shard as usize];
+        let index = rand::thread_rng().gen_range(0..keepers.len());
+
+        keepers[index].get_connection().await
+    }
+
+    /// Applies `USE <keyspace_name>` to every live connection in the pool, and
+    /// remembers it so that keepers spawned later (e.g. if the pool grows after a
+    /// topology change) open their connections with it too.
+    /// Returns the first error encountered, if any, after attempting all of them.
+    pub async fn use_keyspace(
+        &self,
+        keyspace_name: &VerifiedKeyspaceName,
+    ) -> Result<(), QueryError> {
+        *self.used_keyspace.lock().unwrap() = Some(keyspace_name.clone());
+
+        let mut first_error = None;
+
+        for keeper in self.layout.all_keepers() {
+            if let Err(e) = keeper.use_keyspace(keyspace_name).await {
+                first_error.get_or_insert(e);
+            }
+        }
+
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Shuts every connection in the pool down, e.g. when the node is being
+    /// removed from the cluster during a topology change. See
+    /// `ConnectionKeeper::shutdown` for the meaning of `drain`.
+    pub fn shutdown(&self, drain: bool) {
+        for keeper in self.layout.all_keepers() {
+            keeper.shutdown(drain);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PoolLayout;
+    use crate::transport::connection_keeper::ConnectionKeeper;
+
+    // Mirrors `ShardInfo::shard_for_token`'s formula directly, since `ShardInfo`
+    // itself lives outside this chunk: shard = ((token + 2^63) * nr_shards) / 2^64.
+    fn shard_for_token(token_value: i64, nr_shards: u16) -> u16 {
+        let biased_token = (token_value as u64).wrapping_add(1u64 << 63);
+        (((biased_token as u128) * (nr_shards as u128)) >> 64) as u16
+    }
+
+    #[test]
+    fn shard_for_token_is_in_range_and_deterministic() {
+        let nr_shards = 4u16;
+
+        for token_value in [i64::MIN, -1, 0, 1, i64::MAX] {
+            let shard = shard_for_token(token_value, nr_shards);
+            assert!((shard as u16) < nr_shards);
+            assert_eq!(shard, shard_for_token(token_value, nr_shards));
+        }
+    }
+
+    #[test]
+    fn shard_for_token_spans_the_full_shard_range() {
+        let nr_shards = 8u16;
+
+        // Token space is split evenly: the lowest token maps to shard 0,
+        // the highest to the last shard.
+        assert_eq!(shard_for_token(i64::MIN, nr_shards), 0);
+        assert_eq!(shard_for_token(i64::MAX, nr_shards), nr_shards - 1);
+    }
+
+    #[test]
+    fn empty_flat_layout_iterates_no_keepers() {
+        // An empty flat layout has no keepers to iterate - regression guard for
+        // the iterator plumbing in `PoolLayout::all_keepers`.
+        let layout = PoolLayout::Flat(Vec::<ConnectionKeeper>::new());
+        assert_eq!(layout.all_keepers().count(), 0);
+    }
+}